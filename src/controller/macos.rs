@@ -1,13 +1,14 @@
+use std::collections::BTreeMap;
 use std::env;
 use std::fmt;
 use std::fs::{self, File};
-use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::sync::mpsc;
 
 use ctrlc;
 use log::info;
+use serde::Serialize;
 
 use crate::controller::{ControllerInterface, ServiceMainFn};
 use crate::session;
@@ -17,6 +18,33 @@ use crate::ServiceEvent;
 type MacosServiceMainWrapperFn = extern "system" fn(args: Vec<String>);
 pub type Session = session::Session_<u32>;
 
+/// Where a LaunchAgent's plist is installed and loaded. Only meaningful when `is_agent` is
+/// true; daemons always install system-wide and this is ignored.
+#[derive(PartialEq)]
+pub enum InstallScope {
+    /// `/Library/LaunchAgents`, loaded system-wide. Requires root.
+    System,
+    /// `~/Library/LaunchAgents`, loaded into the calling user's `gui/<uid>` domain. Lets a
+    /// non-privileged process install its own login-time agent without sudo.
+    User,
+}
+
+/// Returns the calling user's home directory, used to resolve `InstallScope::User` paths.
+fn home_dir() -> Result<PathBuf, Error> {
+    env::var("HOME")
+        .map(PathBuf::from)
+        .map_err(|e| Error::new(&format!("Failed to resolve HOME: {}", e)))
+}
+
+/// Converts a caller-supplied path to UTF-8, since plist strings can't represent arbitrary
+/// bytes. Returns an `Error` instead of panicking, unlike paths ceviche derives itself
+/// (e.g. `current_exe`) which are assumed valid.
+fn path_to_utf8(path: &Path) -> Result<String, Error> {
+    path.to_str()
+        .map(str::to_string)
+        .ok_or_else(|| Error::new(&format!("path {} is not valid UTF-8", path.display())))
+}
+
 pub enum LaunchAgentTargetSesssion {
     GUI,
     NonGUI,
@@ -35,64 +63,291 @@ impl fmt::Display for LaunchAgentTargetSesssion {
     }
 }
 
-fn launchctl_load_daemon(plist_path: &Path) -> Result<(), Error> {
-    let output = Command::new("launchctl")
-        .arg("load")
-        .arg(&plist_path.to_str().unwrap())
-        .output()
-        .map_err(|e| {
-            Error::new(&format!(
-                "Failed to load plist {}: {}",
-                plist_path.display(),
-                e
-            ))
-        })?;
-    if output.stdout.len() > 0 {
-        info!("{}", String::from_utf8_lossy(&output.stdout));
-    }
-    Ok(())
+/// The launchd operations `MacosController` needs, abstracted so it can run against a real
+/// `launchctl` or a no-op implementation for dry runs and tests.
+trait LaunchdBackend {
+    fn bootstrap(&self, domain: &str, plist_path: &Path) -> Result<(), Error>;
+    fn bootout(&self, target: &str) -> Result<(), Error>;
+    fn kickstart(&self, target: &str) -> Result<(), Error>;
+    fn print_disabled(&self, domain: &str) -> Result<String, Error>;
+    fn enable(&self, target: &str) -> Result<(), Error>;
+    fn write_plist(&self, path: &Path, plist: &LaunchdPlist) -> Result<(), Error>;
+    fn remove_plist(&self, path: &Path) -> Result<(), Error>;
 }
 
-fn launchctl_unload_daemon(plist_path: &Path) -> Result<(), Error> {
-    let output = Command::new("launchctl")
-        .arg("unload")
-        .arg(&plist_path.to_str().unwrap())
-        .output()
-        .map_err(|e| {
-            Error::new(&format!(
-                "Failed to unload plist {}: {}",
-                plist_path.display(),
-                e
-            ))
-        })?;
-    if output.stdout.len() > 0 {
-        info!("{}", String::from_utf8_lossy(&output.stdout));
-    }
-    Ok(())
+/// Talks to the real `launchctl` binary via the domain-target `bootstrap`/`bootout`/`kickstart`
+/// subcommands, which replaced the deprecated `load`/`unload`/`start`/`stop`.
+struct LaunchctlBackend;
+
+impl LaunchdBackend for LaunchctlBackend {
+    fn bootstrap(&self, domain: &str, plist_path: &Path) -> Result<(), Error> {
+        let output = Command::new("launchctl")
+            .arg("bootstrap")
+            .arg(domain)
+            .arg(&plist_path.to_str().unwrap())
+            .output()
+            .map_err(|e| {
+                Error::new(&format!(
+                    "Failed to bootstrap plist {}: {}",
+                    plist_path.display(),
+                    e
+                ))
+            })?;
+        if output.stdout.len() > 0 {
+            info!("{}", String::from_utf8_lossy(&output.stdout));
+        }
+        Ok(())
+    }
+
+    fn bootout(&self, target: &str) -> Result<(), Error> {
+        let output = Command::new("launchctl")
+            .arg("bootout")
+            .arg(target)
+            .output()
+            .map_err(|e| Error::new(&format!("Failed to bootout {}: {}", target, e)))?;
+        if output.stdout.len() > 0 {
+            info!("{}", String::from_utf8_lossy(&output.stdout));
+        }
+        Ok(())
+    }
+
+    fn kickstart(&self, target: &str) -> Result<(), Error> {
+        let output = Command::new("launchctl")
+            .arg("kickstart")
+            .arg("-k")
+            .arg(target)
+            .output()
+            .map_err(|e| Error::new(&format!("Failed to kickstart {}: {}", target, e)))?;
+        if output.stdout.len() > 0 {
+            info!("{}", String::from_utf8_lossy(&output.stdout));
+        }
+        Ok(())
+    }
+
+    fn print_disabled(&self, domain: &str) -> Result<String, Error> {
+        let output = Command::new("launchctl")
+            .arg("print-disabled")
+            .arg(domain)
+            .output()
+            .map_err(|e| Error::new(&format!("Failed to print-disabled {}: {}", domain, e)))?;
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    fn enable(&self, target: &str) -> Result<(), Error> {
+        let output = Command::new("launchctl")
+            .arg("enable")
+            .arg(target)
+            .output()
+            .map_err(|e| Error::new(&format!("Failed to enable {}: {}", target, e)))?;
+        if output.stdout.len() > 0 {
+            info!("{}", String::from_utf8_lossy(&output.stdout));
+        }
+        Ok(())
+    }
+
+    fn write_plist(&self, path: &Path, plist: &LaunchdPlist) -> Result<(), Error> {
+        let file = File::create(path)
+            .map_err(|e| Error::new(&format!("Failed to create {}: {}", path.display(), e)))?;
+        plist::to_writer_xml(file, plist)
+            .map_err(|e| Error::new(&format!("Failed to write {}: {}", path.display(), e)))
+    }
+
+    fn remove_plist(&self, path: &Path) -> Result<(), Error> {
+        fs::remove_file(path)
+            .map_err(|e| Error::new(&format!("Failed to delete {}: {}", path.display(), e)))
+    }
 }
 
-fn launchctl_start_daemon(name: &str) -> Result<(), Error> {
-    let output = Command::new("launchctl")
-        .arg("start")
-        .arg(name)
-        .output()
-        .map_err(|e| Error::new(&format!("Failed to start {}: {}", name, e)))?;
-    if output.stdout.len() > 0 {
-        info!("{}", String::from_utf8_lossy(&output.stdout));
+/// Logs the action it would have taken and reports success without touching launchd. Used
+/// for dry runs and for testing `MacosController` without a real launchd to talk to.
+struct NullBackend;
+
+impl LaunchdBackend for NullBackend {
+    fn bootstrap(&self, domain: &str, plist_path: &Path) -> Result<(), Error> {
+        info!("[null backend] would bootstrap {} into {}", plist_path.display(), domain);
+        Ok(())
     }
-    Ok(())
+
+    fn bootout(&self, target: &str) -> Result<(), Error> {
+        info!("[null backend] would bootout {}", target);
+        Ok(())
+    }
+
+    fn kickstart(&self, target: &str) -> Result<(), Error> {
+        info!("[null backend] would kickstart {}", target);
+        Ok(())
+    }
+
+    fn print_disabled(&self, _domain: &str) -> Result<String, Error> {
+        Ok(String::new())
+    }
+
+    fn enable(&self, target: &str) -> Result<(), Error> {
+        info!("[null backend] would enable {}", target);
+        Ok(())
+    }
+
+    fn write_plist(&self, path: &Path, _plist: &LaunchdPlist) -> Result<(), Error> {
+        info!("[null backend] would write plist {}", path.display());
+        Ok(())
+    }
+
+    fn remove_plist(&self, path: &Path) -> Result<(), Error> {
+        info!("[null backend] would remove plist {}", path.display());
+        Ok(())
+    }
+}
+
+/// Selects which `LaunchdBackend` `MacosController` dispatches through.
+pub enum ServiceManagerKind {
+    /// Drive the real `launchctl` binary. The default.
+    Launchctl,
+    /// Log intended actions and report success without touching launchd.
+    Null,
 }
 
-fn launchctl_stop_daemon(name: &str) -> Result<(), Error> {
-    let output = Command::new("launchctl")
-        .arg("stop")
-        .arg(name)
+impl ServiceManagerKind {
+    fn backend(&self) -> Box<dyn LaunchdBackend> {
+        match self {
+            ServiceManagerKind::Launchctl => Box::new(LaunchctlBackend),
+            ServiceManagerKind::Null => Box::new(NullBackend),
+        }
+    }
+}
+
+/// Returns the effective UID of the calling user, used to build `gui/<uid>` domain targets.
+fn effective_uid() -> Result<u32, Error> {
+    let output = Command::new("id")
+        .arg("-u")
         .output()
-        .map_err(|e| Error::new(&format!("Failed to stop {}: {}", name, e)))?;
-    if output.stdout.len() > 0 {
-        info!("{}", String::from_utf8_lossy(&output.stdout));
+        .map_err(|e| Error::new(&format!("Failed to run id -u: {}", e)))?;
+    String::from_utf8_lossy(&output.stdout)
+        .trim()
+        .parse::<u32>()
+        .map_err(|e| Error::new(&format!("Failed to parse effective uid: {}", e)))
+}
+
+/// Parses `launchctl print-disabled <domain>` output, returning whether `service_name`'s
+/// entry is `true` (disabled). Entries look like `"label" => true;`; absent labels default
+/// to not disabled.
+fn parse_print_disabled(output: &str, service_name: &str) -> bool {
+    let quoted_label = format!("\"{}\"", service_name);
+    for line in output.lines() {
+        let line = line.trim();
+        if !line.starts_with('"') || !line.contains(&quoted_label) {
+            continue;
+        }
+        return line.trim_end_matches(';').trim_end().ends_with("true");
     }
-    Ok(())
+    false
+}
+
+/// One entry of a `StartCalendarInterval` array, matching launchd's calendar-trigger keys.
+/// Any field left `None` is treated by launchd as "every value" for that unit.
+#[derive(Default, Serialize)]
+pub struct StartCalendarInterval {
+    #[serde(rename = "Minute", skip_serializing_if = "Option::is_none")]
+    pub minute: Option<u8>,
+    #[serde(rename = "Hour", skip_serializing_if = "Option::is_none")]
+    pub hour: Option<u8>,
+    #[serde(rename = "Day", skip_serializing_if = "Option::is_none")]
+    pub day: Option<u8>,
+    #[serde(rename = "Weekday", skip_serializing_if = "Option::is_none")]
+    pub weekday: Option<u8>,
+    #[serde(rename = "Month", skip_serializing_if = "Option::is_none")]
+    pub month: Option<u8>,
+}
+
+/// The `KeepAlive` key, which launchd accepts either as a bare bool or as a dict of
+/// conditions (e.g. `{SuccessfulExit: false}`) that must hold for the job to be restarted.
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum KeepAlive {
+    Bool(bool),
+    Conditions(BTreeMap<String, plist::Value>),
+}
+
+/// When launchd should restart the job, translated into a `KeepAlive` bool or condition dict.
+pub enum KeepAlivePolicy {
+    /// Never restart the job; it runs once at load. Emits no `KeepAlive` key.
+    Never,
+    /// Unconditionally restart the job whenever it exits. Emits `KeepAlive = true`.
+    Always,
+    /// Restart only if the job was killed by a signal (crashed). Emits `{Crashed: true}`.
+    OnCrash,
+    /// Restart only while `path` does not exist yet. Emits `{PathState: {path: false}}`.
+    RestartUntilPathExists(PathBuf),
+    /// Restart only if the job's last exit matches `successful`. Emits `{SuccessfulExit: ..}`.
+    SuccessfulExit(bool),
+}
+
+impl KeepAlivePolicy {
+    fn to_keep_alive(&self) -> Result<Option<KeepAlive>, Error> {
+        let keep_alive = match self {
+            KeepAlivePolicy::Never => return Ok(None),
+            KeepAlivePolicy::Always => KeepAlive::Bool(true),
+            KeepAlivePolicy::OnCrash => {
+                let mut conditions = BTreeMap::new();
+                conditions.insert("Crashed".to_string(), plist::Value::Boolean(true));
+                KeepAlive::Conditions(conditions)
+            }
+            KeepAlivePolicy::RestartUntilPathExists(path) => {
+                let mut path_state = plist::Dictionary::new();
+                path_state.insert(path_to_utf8(path)?, plist::Value::Boolean(false));
+                let mut conditions = BTreeMap::new();
+                conditions.insert("PathState".to_string(), plist::Value::Dictionary(path_state));
+                KeepAlive::Conditions(conditions)
+            }
+            KeepAlivePolicy::SuccessfulExit(successful) => {
+                let mut conditions = BTreeMap::new();
+                conditions.insert("SuccessfulExit".to_string(), plist::Value::Boolean(*successful));
+                KeepAlive::Conditions(conditions)
+            }
+        };
+        Ok(Some(keep_alive))
+    }
+}
+
+/// A strongly-typed view of the launchd keys ceviche knows how to populate, serialized to
+/// XML plist via the `plist` crate rather than hand-assembled strings. Optional keys are left
+/// out when the corresponding `MacosController` builder method (or, for agents'
+/// `LimitLoadToSessionType`, the `session_types` field) was never used. The emitted
+/// plist always contains `Disabled`/`Label`/`ProgramArguments`/`RunAtLoad`/`WorkingDirectory`,
+/// and `KeepAlive` unless the policy is `KeepAlivePolicy::Never` (the default is `Always`).
+#[derive(Serialize)]
+pub struct LaunchdPlist {
+    #[serde(rename = "Disabled")]
+    pub disabled: bool,
+    #[serde(rename = "Label")]
+    pub label: String,
+    #[serde(rename = "ProgramArguments")]
+    pub program_arguments: Vec<String>,
+    #[serde(rename = "RunAtLoad")]
+    pub run_at_load: bool,
+    #[serde(rename = "WorkingDirectory", skip_serializing_if = "Option::is_none")]
+    pub working_directory: Option<String>,
+    #[serde(rename = "EnvironmentVariables", skip_serializing_if = "Option::is_none")]
+    pub environment_variables: Option<BTreeMap<String, String>>,
+    #[serde(rename = "StandardOutPath", skip_serializing_if = "Option::is_none")]
+    pub standard_out_path: Option<String>,
+    #[serde(rename = "StandardErrorPath", skip_serializing_if = "Option::is_none")]
+    pub standard_error_path: Option<String>,
+    #[serde(rename = "StartInterval", skip_serializing_if = "Option::is_none")]
+    pub start_interval: Option<u32>,
+    #[serde(rename = "StartCalendarInterval", skip_serializing_if = "Option::is_none")]
+    pub start_calendar_interval: Option<StartCalendarInterval>,
+    #[serde(rename = "ThrottleInterval", skip_serializing_if = "Option::is_none")]
+    pub throttle_interval: Option<u32>,
+    #[serde(rename = "ProcessType", skip_serializing_if = "Option::is_none")]
+    pub process_type: Option<String>,
+    #[serde(rename = "UserName", skip_serializing_if = "Option::is_none")]
+    pub user_name: Option<String>,
+    #[serde(rename = "GroupName", skip_serializing_if = "Option::is_none")]
+    pub group_name: Option<String>,
+    #[serde(rename = "LimitLoadToSessionType", skip_serializing_if = "Option::is_none")]
+    pub limit_load_to_session_type: Option<Vec<String>>,
+    #[serde(rename = "KeepAlive", skip_serializing_if = "Option::is_none")]
+    pub keep_alive: Option<KeepAlive>,
 }
 
 pub struct MacosController {
@@ -102,7 +357,21 @@ pub struct MacosController {
     pub description: String,
     pub is_agent: bool,
     pub session_types: Option<Vec<LaunchAgentTargetSesssion>>,
-    pub keep_alive: bool,
+    pub keep_alive: KeepAlivePolicy,
+    pub domain: Option<String>,
+    install_scope: InstallScope,
+    service_manager: ServiceManagerKind,
+    program_arguments: Option<Vec<String>>,
+    environment: Option<BTreeMap<String, String>>,
+    stdout_path: Option<PathBuf>,
+    stderr_path: Option<PathBuf>,
+    working_directory: Option<PathBuf>,
+    start_interval: Option<u32>,
+    start_calendar_interval: Option<StartCalendarInterval>,
+    throttle_interval: Option<u32>,
+    process_type: Option<String>,
+    user_name: Option<String>,
+    group_name: Option<String>,
 }
 
 impl MacosController {
@@ -113,8 +382,152 @@ impl MacosController {
             description: description.to_string(),
             is_agent: false,
             session_types: None,
-            keep_alive: true,
+            keep_alive: KeepAlivePolicy::Always,
+            domain: None,
+            install_scope: InstallScope::System,
+            service_manager: ServiceManagerKind::Launchctl,
+            program_arguments: None,
+            environment: None,
+            stdout_path: None,
+            stderr_path: None,
+            working_directory: None,
+            start_interval: None,
+            start_calendar_interval: None,
+            throttle_interval: None,
+            process_type: None,
+            user_name: None,
+            group_name: None,
+        }
+    }
+
+    /// Overrides `ProgramArguments`; the first element is conventionally the executable path.
+    /// Defaults to `vec![current_exe]` when never called.
+    pub fn with_program_arguments(mut self, arguments: Vec<String>) -> Self {
+        self.program_arguments = Some(arguments);
+        self
+    }
+
+    /// Sets `EnvironmentVariables` for the launched process.
+    pub fn with_environment(mut self, environment: BTreeMap<String, String>) -> Self {
+        self.environment = Some(environment);
+        self
+    }
+
+    /// Sets `StandardOutPath`.
+    pub fn with_stdout_path(mut self, path: PathBuf) -> Self {
+        self.stdout_path = Some(path);
+        self
+    }
+
+    /// Sets `StandardErrorPath`.
+    pub fn with_stderr_path(mut self, path: PathBuf) -> Self {
+        self.stderr_path = Some(path);
+        self
+    }
+
+    /// Overrides `WorkingDirectory`. Defaults to the current executable's parent directory.
+    pub fn with_working_directory(mut self, path: PathBuf) -> Self {
+        self.working_directory = Some(path);
+        self
+    }
+
+    /// Sets `StartInterval`, running the job every `seconds` seconds.
+    pub fn with_start_interval(mut self, seconds: u32) -> Self {
+        self.start_interval = Some(seconds);
+        self
+    }
+
+    /// Sets `StartCalendarInterval`, running the job on a calendar schedule.
+    pub fn with_start_calendar_interval(mut self, interval: StartCalendarInterval) -> Self {
+        self.start_calendar_interval = Some(interval);
+        self
+    }
+
+    /// Sets `ThrottleInterval`, the minimum number of seconds launchd waits between restarts.
+    pub fn with_throttle_interval(mut self, seconds: u32) -> Self {
+        self.throttle_interval = Some(seconds);
+        self
+    }
+
+    /// Sets the `KeepAlive` restart policy. Defaults to `KeepAlivePolicy::Always`.
+    pub fn with_keep_alive(mut self, policy: KeepAlivePolicy) -> Self {
+        self.keep_alive = policy;
+        self
+    }
+
+    /// Sets `ProcessType`, e.g. `"Interactive"`, `"Adaptive"`, `"Background"`, `"Standard"`.
+    pub fn with_process_type(mut self, process_type: &str) -> Self {
+        self.process_type = Some(process_type.to_string());
+        self
+    }
+
+    /// Sets `UserName`, the user the job should run as.
+    pub fn with_user_name(mut self, user_name: &str) -> Self {
+        self.user_name = Some(user_name.to_string());
+        self
+    }
+
+    /// Sets `GroupName`, the group the job should run as.
+    pub fn with_group_name(mut self, group_name: &str) -> Self {
+        self.group_name = Some(group_name.to_string());
+        self
+    }
+
+    /// Overrides the launchd domain target (e.g. `system` or `gui/501`). When unset, it is
+    /// derived from `is_agent`: daemons target `system`, agents target `gui/<effective uid>`.
+    pub fn with_domain(mut self, domain: &str) -> Self {
+        self.domain = Some(domain.to_string());
+        self
+    }
+
+    /// Sets the install scope; `InstallScope::User` only takes effect when `is_agent` is true.
+    pub fn with_install_scope(mut self, install_scope: InstallScope) -> Self {
+        self.install_scope = install_scope;
+        self
+    }
+
+    /// Sets which backend `launchctl`-equivalent operations dispatch through, e.g.
+    /// `ServiceManagerKind::Null` to exercise this controller in a test or sandboxed
+    /// environment without a usable launchd.
+    pub fn with_service_manager(mut self, service_manager: ServiceManagerKind) -> Self {
+        self.service_manager = service_manager;
+        self
+    }
+
+    /// Returns the launchd domain target this controller operates in, e.g. `system` or
+    /// `gui/501`. Defaults can be overridden by setting `domain` directly.
+    pub fn domain(&self) -> Result<String, Error> {
+        if let Some(domain) = &self.domain {
+            return Ok(domain.clone());
+        }
+        if self.is_agent {
+            Ok(format!("gui/{}", effective_uid()?))
+        } else {
+            Ok("system".to_string())
+        }
+    }
+
+    /// Returns the `<domain>/<service_name>` target string used by `launchctl` subcommands
+    /// that operate on a service target rather than a bare name or plist path.
+    fn service_target(&self) -> Result<String, Error> {
+        Ok(format!("{}/{}", self.domain()?, self.service_name))
+    }
+
+    /// Checks whether `service_name` is marked disabled in launchd's persistent override
+    /// database for this controller's domain. `bootstrap`/`kickstart` fail silently against
+    /// a disabled service even when its plist is valid, so callers should check this (or
+    /// rely on `create`/`start` doing so automatically) before reporting a launch failure.
+    pub fn service_is_disabled(&self) -> Result<bool, Error> {
+        let output = self.service_manager.backend().print_disabled(&self.domain()?)?;
+        Ok(parse_print_disabled(&output, &self.service_name))
+    }
+
+    /// Re-enables the service if launchd's override database reports it disabled.
+    fn ensure_enabled(&self) -> Result<(), Error> {
+        if self.service_is_disabled()? {
+            self.service_manager.backend().enable(&self.service_target()?)?;
         }
+        Ok(())
     }
 
     /// Register the `service_main_wrapper` function, this function is generated by the `Service!` macro.
@@ -126,121 +539,123 @@ impl MacosController {
         Ok(())
     }
 
-    fn get_plist_content(&self) -> Result<String, Error> {
-        let mut current_exe = env::current_exe()
-            .map_err(|e| Error::new(&format!("env::current_exe() failed: {}", e)))?;
-        let current_exe_str = current_exe
-            .to_str().expect("current_exe path to be unicode").to_string();
-
-        current_exe.pop();
-        let working_dir_str = current_exe
-            .to_str().expect("working_dir path to be unicode");
-
-        let mut plist = String::new();
-        plist.push_str(r#"
-<?xml version="1.0" encoding="UTF-8"?>
-<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
-<plist version="1.0">
-<dict>"#);
-
-        plist.push_str(&format!(r#"
-<key>Disabled</key>
-<false/>
-<key>Label</key>
-<string>{}</string>
-<key>ProgramArguments</key>
-<array>
-<string>{}</string>
-</array>
-<key>WorkingDirectory</key>
-<string>{}</string>
-<key>RunAtLoad</key>
-<true/>"#,
-            self.service_name,
-            current_exe_str,
-            working_dir_str,
-        ));
+    /// Builds the typed plist model from the controller's current configuration, filling in
+    /// `ProgramArguments`/`WorkingDirectory` from the current executable when the caller
+    /// never overrode them via `with_program_arguments`/`with_working_directory`.
+    fn to_launchd_plist(&self) -> Result<LaunchdPlist, Error> {
+        let program_arguments = match &self.program_arguments {
+            Some(arguments) => arguments.clone(),
+            None => {
+                let current_exe = env::current_exe()
+                    .map_err(|e| Error::new(&format!("env::current_exe() failed: {}", e)))?;
+                vec![current_exe
+                    .to_str()
+                    .expect("current_exe path to be unicode")
+                    .to_string()]
+            }
+        };
 
-        if self.is_agent {
-            if let Some(session_types) = self.session_types.as_ref() {
-                plist.push_str(r#"
-<key>LimitLoadToSessionType</key>
-<array>"#);
-
-                for session_type in session_types {
-                    plist.push_str(&format!(r#"
-<string>{}</string>"#, session_type));
-                }
-
-                plist.push_str(r#"
-</array>"#);
+        let working_directory = match &self.working_directory {
+            Some(path) => Some(path_to_utf8(path)?),
+            None => {
+                let mut current_exe = env::current_exe()
+                    .map_err(|e| Error::new(&format!("env::current_exe() failed: {}", e)))?;
+                current_exe.pop();
+                Some(current_exe.to_str().expect("working_dir path to be unicode").to_string())
             }
-        }
+        };
 
-        if self.keep_alive {
-            plist.push_str(r#"
-<key>KeepAlive</key>
-<true/>"#);
-        }
+        let limit_load_to_session_type = if self.is_agent {
+            self.session_types
+                .as_ref()
+                .map(|types| types.iter().map(|t| t.to_string()).collect())
+        } else {
+            None
+        };
 
-        plist.push_str(r#"
-</dict>
-</plist>"#);
+        let keep_alive = self.keep_alive.to_keep_alive()?;
 
-        Ok(plist)
+        Ok(LaunchdPlist {
+            disabled: false,
+            label: self.service_name.clone(),
+            program_arguments,
+            run_at_load: true,
+            working_directory,
+            environment_variables: self.environment.clone(),
+            standard_out_path: self.stdout_path.as_ref().map(|p| path_to_utf8(p)).transpose()?,
+            standard_error_path: self.stderr_path.as_ref().map(|p| path_to_utf8(p)).transpose()?,
+            start_interval: self.start_interval,
+            start_calendar_interval: self.start_calendar_interval.as_ref().map(|i| StartCalendarInterval {
+                minute: i.minute,
+                hour: i.hour,
+                day: i.day,
+                weekday: i.weekday,
+                month: i.month,
+            }),
+            throttle_interval: self.throttle_interval,
+            process_type: self.process_type.clone(),
+            user_name: self.user_name.clone(),
+            group_name: self.group_name.clone(),
+            limit_load_to_session_type,
+            keep_alive,
+        })
     }
 
     fn write_plist(&self, path: &Path) -> Result<(), Error> {
         info!("Writing plist file {}", path.display());
-        let content = self.get_plist_content()?;
-        File::create(path)
-            .and_then(|mut file| file.write_all(content.as_bytes()))
-            .map_err(|e| Error::new(&format!("Failed to write {}: {}", path.display(), e)))
-
+        let plist = self.to_launchd_plist()?;
+        self.service_manager.backend().write_plist(path, &plist)
     }
 
-    fn plist_path(&mut self) -> PathBuf {
-        Path::new("/Library/")
+    fn plist_path(&mut self) -> Result<PathBuf, Error> {
+        if self.is_agent && self.install_scope == InstallScope::User {
+            return Ok(home_dir()?
+                .join("Library/LaunchAgents")
+                .join(format!("{}.plist", &self.service_name)));
+        }
+        Ok(Path::new("/Library/")
         .join(if self.is_agent { "LaunchAgents/" } else { "LaunchDaemons/"})
-        .join(format!("{}.plist", &self.service_name))
+        .join(format!("{}.plist", &self.service_name)))
     }
 }
 
 impl ControllerInterface for MacosController {
     /// Creates the service on the system.
     fn create(&mut self) -> Result<(), Error> {
-        let plist_path = self.plist_path();
-            
+        let plist_path = self.plist_path()?;
+
         self.write_plist(&plist_path)?;
         if !self.is_agent {
-            return launchctl_load_daemon(&plist_path)
+            self.ensure_enabled()?;
+            return self.service_manager.backend().bootstrap(&self.domain()?, &plist_path)
         }
         Ok(())
     }
     /// Deletes the service.
     fn delete(&mut self) -> Result<(), Error> {
-        let plist_path = self.plist_path();
+        let plist_path = self.plist_path()?;
         if !self.is_agent {
-            launchctl_unload_daemon(&plist_path)?;
+            self.service_manager.backend().bootout(&self.service_target()?)?;
         }
-        fs::remove_file(&plist_path)
-            .map_err(|e| Error::new(&format!("Failed to delete {}: {}", plist_path.display(), e)))
+        self.service_manager.backend().remove_plist(&plist_path)
     }
     /// Starts the service.
     fn start(&mut self) -> Result<(), Error> {
-        launchctl_start_daemon(&self.service_name)
+        self.ensure_enabled()?;
+        self.service_manager.backend().kickstart(&self.service_target()?)
     }
     /// Stops the service.
     fn stop(&mut self) -> Result<(), Error> {
-        launchctl_stop_daemon(&self.service_name)
+        self.service_manager.backend().bootout(&self.service_target()?)
     }
     // Loads the agent service.
     fn load(&mut self) -> Result<(), Error> {
-        launchctl_load_daemon(&self.plist_path())
+        let plist_path = self.plist_path()?;
+        self.service_manager.backend().bootstrap(&self.domain()?, &plist_path)
     }
     // Loads the agent service.
     fn unload(&mut self) -> Result<(), Error> {
-        launchctl_unload_daemon(&self.plist_path())
+        self.service_manager.backend().bootout(&self.service_target()?)
     }
 }
 
@@ -263,4 +678,51 @@ pub fn dispatch<T: Send + 'static>(service_main: ServiceMainFn<T>, args: Vec<Str
         let _ = tx.send(ServiceEvent::Stop);
     }).expect("Failed to register Ctrl-C handler");
     service_main(rx, _tx, args, false);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn null_controller() -> MacosController {
+        MacosController::new("com.ceviche.test", "Test Service", "A test service")
+            .with_service_manager(ServiceManagerKind::Null)
+    }
+
+    #[test]
+    fn create_start_stop_delete_do_not_touch_the_real_system() {
+        let mut controller = null_controller();
+        assert!(controller.create().is_ok());
+        assert!(controller.start().is_ok());
+        assert!(controller.stop().is_ok());
+        assert!(controller.delete().is_ok());
+    }
+
+    const PRINT_DISABLED_OUTPUT: &str = r#"disabled services = {
+    "com.ceviche.test" => true
+    "com.ceviche.test.other" => false
+}"#;
+
+    #[test]
+    fn parse_print_disabled_finds_a_disabled_service() {
+        assert!(parse_print_disabled(PRINT_DISABLED_OUTPUT, "com.ceviche.test"));
+    }
+
+    #[test]
+    fn parse_print_disabled_finds_an_enabled_service() {
+        assert!(!parse_print_disabled(PRINT_DISABLED_OUTPUT, "com.ceviche.test.other"));
+    }
+
+    #[test]
+    fn parse_print_disabled_defaults_to_not_disabled_when_absent() {
+        assert!(!parse_print_disabled(PRINT_DISABLED_OUTPUT, "com.ceviche.missing"));
+    }
+
+    #[test]
+    fn parse_print_disabled_does_not_match_on_a_label_prefix() {
+        // "com.ceviche.test" is a prefix of "com.ceviche.test.other"; the longer label's
+        // (differing) entry comes first, so a naive substring match would pick it up.
+        let output = "\"com.ceviche.test.other\" => true\n\"com.ceviche.test\" => false";
+        assert!(!parse_print_disabled(output, "com.ceviche.test"));
+    }
 }
\ No newline at end of file